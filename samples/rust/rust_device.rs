@@ -6,9 +6,11 @@ use kernel::{
     Module,
     miscdev,
     prelude::*,
-    file::{File, Operations},
+    file::{File, IoctlCommand, IoctlHandler, Operations, SeekFrom},
+    io_buffer::{IoBufferReader, IoBufferWriter},
+    ioctl::{_IO, _IOR, _IOW, _IOWR},
     sync::{Arc, ArcBorrow, smutex::Mutex},
-    io_buffer::{IoBufferReader, IoBufferWriter}
+    user_ptr::{UserSlicePtrReader, UserSlicePtrWriter},
 };
 
 
@@ -18,53 +20,458 @@ module! {
     author: "David English",
     description: "Rust Device Linux Kernel Module",
     license: "GPL",
+    params: {
+        capacity: usize {
+            default: 0,
+            permissions: 0o444,
+            description: "Fixed device size in blocks (0 = grow on demand)",
+        },
+        shards: usize {
+            default: 8,
+            permissions: 0o444,
+            description: "Number of independently-locked storage shards (>= 1)",
+        },
+    },
 }
 
 const BLOCK_SIZE : usize = 4096;
 
-struct Device {
-  data:  Mutex<Vec<Vec<u8>>>,
-  cursor: Mutex<usize>
+// ioctl command space for the device. Mirrors the PunchHole/SeekData/SeekHole
+// operations the virtio-blk backend exposes for sparse images.
+const RUST_DEV_MAGIC : u32 = 'R' as u32;
+// Deallocate the rows fully covered by a (offset, length) range.
+const RUST_DEV_PUNCH_HOLE : u32 = _IOW::<Range>(RUST_DEV_MAGIC, 0x10);
+// Given an offset (in bytes), report the next allocated block (SEEK_DATA).
+const RUST_DEV_SEEK_DATA : u32 = _IOWR::<u64>(RUST_DEV_MAGIC, 0x11);
+// Given an offset (in bytes), report the next unallocated block (SEEK_HOLE).
+const RUST_DEV_SEEK_HOLE : u32 = _IOWR::<u64>(RUST_DEV_MAGIC, 0x12);
+// Capture a copy-on-write snapshot of the current logical state; the new
+// snapshot's id is returned as the ioctl result.
+const RUST_DEV_SNAPSHOT : u32 = _IO(RUST_DEV_MAGIC, 0x13);
+// Roll the active logical state back to the snapshot with the given id.
+const RUST_DEV_SNAPSHOT_RESTORE : u32 = _IOW::<u64>(RUST_DEV_MAGIC, 0x17);
+// Release the snapshot with the given id, dropping its pinned blocks.
+const RUST_DEV_SNAPSHOT_RELEASE : u32 = _IOW::<u64>(RUST_DEV_MAGIC, 0x18);
+// Geometry queries, mirroring BLKGETSIZE64/BLKGETSIZE/BLKSSZGET.
+// Device size in bytes.
+const RUST_DEV_GET_SIZE64 : u32 = _IOR::<u64>(RUST_DEV_MAGIC, 0x14);
+// Device size in 512-byte sectors.
+const RUST_DEV_GET_SECTORS : u32 = _IOR::<u64>(RUST_DEV_MAGIC, 0x15);
+// Logical block size in bytes.
+const RUST_DEV_GET_BLOCK_SIZE : u32 = _IOR::<u64>(RUST_DEV_MAGIC, 0x16);
+
+// Sector size assumed by the BLKGETSIZE-style sector count.
+const SECTOR_SIZE : u64 = 512;
+
+/// A byte range passed to [`RUST_DEV_PUNCH_HOLE`], matching the userspace
+/// layout of two native-endian `u64`s.
+struct Range {
+    offset: u64,
+    length: u64,
 }
 
-impl Device {
-    fn try_new() -> Result<Self> {
-      let set = Vec::<Vec<u8>>::try_with_capacity(BLOCK_SIZE)?;
-      Ok(Self {
-        data: Mutex::new(set),
-        cursor: Mutex::new(0)
-      })
+// A physical block in a shard's pool: `(refcount, bytes)`. A refcount of 0
+// marks a reclaimed slot whose backing `Vec` has been emptied.
+type Pool = Vec<(u32, Vec<u8>)>;
+
+// One independently-locked slice of the device. Logical rows are striped across
+// shards by `row % shards`; the shard-local index is `row / shards`. Keeping the
+// mapping, block pool and snapshots together in one lock means copy-on-write
+// sharing never spans a lock, so writes to disjoint regions proceed in parallel.
+struct Shard {
+  // Shard-local row -> physical block id, or `None` for an unwritten (hole) row.
+  mapping: Vec<Option<usize>>,
+  // Reference-counted physical blocks shared within this shard and its snapshots.
+  pool: Pool,
+  // Retained snapshot mapping tables, indexed by snapshot id; holding one keeps
+  // its blocks' refcounts above one so the active mapping breaks sharing on
+  // write. A released snapshot becomes `None`, leaving later ids stable.
+  snapshots: Vec<Option<Vec<Option<usize>>>>,
+}
+
+impl Shard {
+    fn new() -> Self {
+        Self { mapping: Vec::new(), pool: Vec::new(), snapshots: Vec::new() }
     }
 
+    // Allocates a fresh, zero-filled physical block with refcount 1 and returns
+    // its id in this shard's pool.
+    fn alloc_block(&mut self) -> Result<usize> {
+        let mut block = Vec::<u8>::new();
+        if block.try_resize(BLOCK_SIZE, 0).is_err() {
+            pr_err!("OOM while allocating {} bytes for a physical block\n", BLOCK_SIZE);
+            return Err(ENOMEM);
+        }
+        match self.pool.try_push((1, block)) {
+            Ok(_) => Ok(self.pool.len() - 1),
+            Err(_) => {
+                pr_err!("OOM growing the block pool to {} entries\n", self.pool.len() + 1);
+                Err(ENOMEM)
+            }
+        }
+    }
 
-    fn find_block( &self, row: usize) -> Result<usize> {
-        let mut dat = self.data.lock();
-        if row >= dat.len() {
-            let fill = row.saturating_sub(dat.len()) + 1;
+    // Ensures shard-local `lrow` exists in the mapping and is backed by a physical
+    // block, allocating one on demand, and returns its physical id. `row` is the
+    // global logical row and `capacity` the device bound in blocks (0 = grow on
+    // demand); a row past a fixed capacity has nowhere to land, so the mapping is
+    // left untouched and the allocation is refused with `-ENOSPC`.
+    fn find_block(&mut self, lrow: usize, row: usize, capacity: usize) -> Result<usize> {
+        if capacity > 0 && row >= capacity {
+            return Err(ENOSPC);
+        }
+        if lrow >= self.mapping.len() {
+            let fill = lrow.saturating_sub(self.mapping.len()) + 1;
                 for _i in 0..fill {
-                    match dat.try_push(Vec::<u8>::new()) {
+                    match self.mapping.try_push(None) {
                         Ok(_) => continue,
                         Err(_) => {
-                            pr_err!("OOM creating row {}\n", dat.len());
+                            pr_err!("OOM creating row {}\n", self.mapping.len());
                             return Err(ENOMEM)
                         }
                     }
                 }
         }
-        if dat[row].len() != BLOCK_SIZE {
-            match dat[row].try_resize(BLOCK_SIZE, 0) {
-                Ok(..) => Ok(BLOCK_SIZE),
-                Err(..) => {
-                    pr_err!("OOM while allocating {} bytes for block {}\n", BLOCK_SIZE, row);
-                    Err(ENOMEM)
+        match self.mapping[lrow] {
+            Some(pid) => Ok(pid),
+            None => {
+                let pid = self.alloc_block()?;
+                self.mapping[lrow] = Some(pid);
+                Ok(pid)
+            }
+        }
+    }
+
+    // Breaks sharing ahead of a write to `lrow`'s physical block `pid`: if the
+    // block is referenced more than once, copies it into a fresh pool entry,
+    // drops the shared block's refcount, repoints the logical row, and returns
+    // the private block id. Otherwise `pid` is already private and returned as-is.
+    fn cow_break(&mut self, lrow: usize, pid: usize) -> Result<usize> {
+        if self.pool[pid].0 <= 1 {
+            return Ok(pid);
+        }
+        let mut copy = Vec::<u8>::new();
+        if copy.try_resize(BLOCK_SIZE, 0).is_err() {
+            pr_err!("OOM breaking sharing for block {}\n", pid);
+            return Err(ENOMEM);
+        }
+        copy.copy_from_slice(&self.pool[pid].1);
+        let new_pid = match self.pool.try_push((1, copy)) {
+            Ok(_) => self.pool.len() - 1,
+            Err(_) => {
+                pr_err!("OOM growing the block pool to {} entries\n", self.pool.len() + 1);
+                return Err(ENOMEM);
+            }
+        };
+        self.pool[pid].0 -= 1;
+        self.mapping[lrow] = Some(new_pid);
+        Ok(new_pid)
+    }
+}
+
+struct Device {
+  // Independently-locked shards; logical rows are striped across them by modulo.
+  shards: Vec<Mutex<Shard>>,
+  // Maximum number of logical blocks, or 0 for grow-on-demand.
+  capacity: usize,
+}
+
+impl Device {
+    fn try_new(shards: usize, capacity: usize) -> Result<Self> {
+      let count = shards.max(1);
+      let mut vec = Vec::<Mutex<Shard>>::try_with_capacity(count)?;
+      for _ in 0..count {
+          vec.try_push(Mutex::new(Shard::new()))?;
+      }
+      Ok(Self {
+        shards: vec,
+        capacity,
+      })
+    }
+
+    // Routes a logical row to its owning shard and shard-local index.
+    fn route(&self, row: usize) -> (usize, usize) {
+        let s = self.shards.len();
+        (row % s, row / s)
+    }
+
+    // Total addressable size in bytes: the configured capacity when fixed,
+    // otherwise the current logical size.
+    fn capacity_bytes(&self) -> u64 {
+        if self.capacity > 0 {
+            (self.capacity as u64) * BLOCK_SIZE as u64
+        } else {
+            self.size()
+        }
+    }
+
+    // One past the highest logical row present in any shard's mapping, i.e. the
+    // addressable extent including interior holes. Bounds the SEEK_DATA/SEEK_HOLE
+    // scan.
+    fn extent(&self) -> usize {
+        let s = self.shards.len();
+        let mut rows = 0;
+        for (shard_idx, shard) in self.shards.iter().enumerate() {
+            let shard = shard.lock();
+            if !shard.mapping.is_empty() {
+                rows = rows.max((shard.mapping.len() - 1) * s + shard_idx + 1);
+            }
+        }
+        rows
+    }
+
+    /// Captures a copy-on-write snapshot of the current logical state: for each
+    /// shard, clones the mapping table, bumps the refcount of every referenced
+    /// physical block, and retains the copy so subsequent writes break sharing
+    /// against it. Gives O(rows) snapshots with storage shared across clones.
+    /// Returns the snapshot's id, for use with [`Device::restore_snapshot`] and
+    /// [`Device::release_snapshot`].
+    fn snapshot(&self) -> Result<usize> {
+        // Every shard grows its `snapshots` vector in lockstep, so the id is the
+        // same in each; the value recorded from the last shard is returned.
+        let mut id = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock();
+            let mut snap = Vec::<Option<usize>>::try_with_capacity(shard.mapping.len())?;
+            for entry in shard.mapping.iter() {
+                snap.try_push(*entry)?;
+            }
+            for entry in snap.iter().flatten() {
+                shard.pool[*entry].0 = shard.pool[*entry].0.saturating_add(1);
+            }
+            id = shard.snapshots.len();
+            shard.snapshots.try_push(Some(snap))?;
+        }
+        Ok(id)
+    }
+
+    /// Rolls the active logical state back to snapshot `id`: each shard drops the
+    /// references held by its current mapping, then adopts a copy of the
+    /// snapshot's mapping and re-pins every referenced block so the snapshot
+    /// itself stays live. Fails with `-EINVAL` for an unknown or released id,
+    /// touching nothing. Subsequent reads observe the restored contents.
+    fn restore_snapshot(&self, id: usize) -> Result<()> {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock();
+            // Clone the snapshot's mapping before mutating anything; a bad id
+            // bails out of the first shard before any state is changed, and the
+            // lockstep lengths guarantee the remaining shards agree.
+            let snap = match shard.snapshots.get(id) {
+                Some(Some(snap)) => {
+                    let mut copy = Vec::<Option<usize>>::try_with_capacity(snap.len())?;
+                    for entry in snap.iter() {
+                        copy.try_push(*entry)?;
+                    }
+                    copy
+                }
+                _ => return Err(EINVAL),
+            };
+            for lrow in 0..shard.mapping.len() {
+                if let Some(pid) = shard.mapping[lrow] {
+                    shard.pool[pid].0 = shard.pool[pid].0.saturating_sub(1);
+                    if shard.pool[pid].0 == 0 {
+                        shard.pool[pid].1 = Vec::<u8>::new();
+                    }
                 }
             }
+            for entry in snap.iter().flatten() {
+                shard.pool[*entry].0 = shard.pool[*entry].0.saturating_add(1);
+            }
+            shard.mapping = snap;
+        }
+        Ok(())
+    }
+
+    /// Drops snapshot `id`, decrementing the refcount of every block it pinned
+    /// and emptying any pool slot that reaches zero. The id becomes `None` so
+    /// later ids keep their value. Fails with `-EINVAL` for an unknown or
+    /// already-released id, touching nothing.
+    fn release_snapshot(&self, id: usize) -> Result<()> {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock();
+            let snap = match shard.snapshots.get_mut(id) {
+                Some(slot) if slot.is_some() => slot.take().unwrap(),
+                _ => return Err(EINVAL),
+            };
+            for entry in snap.iter().flatten() {
+                shard.pool[*entry].0 = shard.pool[*entry].0.saturating_sub(1);
+                if shard.pool[*entry].0 == 0 {
+                    shard.pool[*entry].1 = Vec::<u8>::new();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Current logical size in bytes: one past the highest allocated row,
+    /// multiplied by [`BLOCK_SIZE`]. Used to resolve `SEEK_END`.
+    fn size(&self) -> u64 {
+        let s = self.shards.len();
+        let mut rows = 0;
+        for (shard_idx, shard) in self.shards.iter().enumerate() {
+            let shard = shard.lock();
+            for (lrow, entry) in shard.mapping.iter().enumerate() {
+                if entry.is_some() {
+                    rows = rows.max(lrow * s + shard_idx + 1);
+                }
+            }
+        }
+        (rows as u64) * BLOCK_SIZE as u64
+    }
+
+    /// Deallocates every row that is fully covered by `[offset, offset + length)`,
+    /// returning its backing memory to the allocator. A row is cleared by dropping
+    /// its mapping and its reference on the physical block; the last reference
+    /// empties the pool slot. Rows only partially covered are left untouched,
+    /// matching `FALLOC_FL_PUNCH_HOLE` semantics.
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<usize> {
+        let end = offset.checked_add(length).ok_or(EINVAL)?;
+        // First and last rows wholly inside the range.
+        let bs = BLOCK_SIZE as u64;
+        let first = offset.checked_add(bs - 1).ok_or(EINVAL)? / bs;
+        let last = end / bs;
+        let first : usize = first.try_into()?;
+        let stop : usize = last.try_into().unwrap_or(usize::MAX);
+        let s = self.shards.len();
+        let mut freed = 0;
+        for (shard_idx, shard) in self.shards.iter().enumerate() {
+            let mut shard = shard.lock();
+            // Only this shard's rows whose global index lands in [first, stop):
+            // global row = lrow * s + shard_idx, so bound the scan rather than
+            // walking the whole mapping.
+            let begin = if shard_idx >= first {
+                0
+            } else {
+                (first - shard_idx + s - 1) / s
+            };
+            let end = if shard_idx >= stop {
+                0
+            } else {
+                (stop - shard_idx).saturating_add(s - 1) / s
+            };
+            let end = end.min(shard.mapping.len());
+            for lrow in begin..end {
+                if let Some(pid) = shard.mapping[lrow].take() {
+                    shard.pool[pid].0 = shard.pool[pid].0.saturating_sub(1);
+                    if shard.pool[pid].0 == 0 {
+                        shard.pool[pid].1 = Vec::<u8>::new();
+                    }
+                    freed = freed.saturating_add(1);
+                }
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Scans forward from the row containing `offset` for the next allocated
+    /// ("data") or unallocated ("hole") block, returning the byte offset of its
+    /// start. Mirrors `SEEK_DATA`/`SEEK_HOLE`: a row is "data" when it maps to a
+    /// physical block, otherwise it is a hole.
+    fn seek_block(&self, offset: u64, want_data: bool) -> Result<u64> {
+        let start : usize = (offset / BLOCK_SIZE as u64).try_into()?;
+        let extent = self.extent();
+        for row in start..extent {
+            let (shard_idx, lrow) = self.route(row);
+            let shard = self.shards[shard_idx].lock();
+            let is_data = lrow < shard.mapping.len() && shard.mapping[lrow].is_some();
+            if is_data == want_data {
+                return Ok((row as u64) * BLOCK_SIZE as u64);
+            }
+        }
+        // No data left: SEEK_DATA fails with -ENXIO; SEEK_HOLE reports the
+        // implicit hole at end-of-device, or the starting row itself when that
+        // already lies past it.
+        if want_data {
+            Err(ENXIO)
         } else {
-            return Ok(BLOCK_SIZE);
+            Ok((start.max(extent) as u64) * BLOCK_SIZE as u64)
         }
     }
 }
 
+impl IoctlHandler for Device {
+    type Target<'a> = ArcBorrow<'a, Device>;
+
+    fn pure(this: Self::Target<'_>, _file: &File, cmd: u32, _arg: usize) -> Result<i32> {
+        match cmd {
+            RUST_DEV_SNAPSHOT => {
+                let id = this.snapshot()?;
+                Ok(id.try_into()?)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    fn read(
+        this: Self::Target<'_>,
+        _file: &File,
+        cmd: u32,
+        writer: &mut UserSlicePtrWriter,
+    ) -> Result<i32> {
+        let value = match cmd {
+            RUST_DEV_GET_SIZE64 => this.capacity_bytes(),
+            RUST_DEV_GET_SECTORS => this.capacity_bytes() / SECTOR_SIZE,
+            RUST_DEV_GET_BLOCK_SIZE => BLOCK_SIZE as u64,
+            _ => return Err(EINVAL),
+        };
+        writer.write_slice(&value.to_ne_bytes())?;
+        Ok(0)
+    }
+
+    fn write(
+        this: Self::Target<'_>,
+        _file: &File,
+        cmd: u32,
+        reader: &mut UserSlicePtrReader,
+    ) -> Result<i32> {
+        match cmd {
+            RUST_DEV_PUNCH_HOLE => {
+                let mut bytes = [0u8; 16];
+                reader.read_slice(&mut bytes)?;
+                let range = Range {
+                    offset: u64::from_ne_bytes(bytes[0..8].try_into().unwrap()),
+                    length: u64::from_ne_bytes(bytes[8..16].try_into().unwrap()),
+                };
+                this.punch_hole(range.offset, range.length)?;
+                Ok(0)
+            }
+            RUST_DEV_SNAPSHOT_RESTORE => {
+                let mut bytes = [0u8; 8];
+                reader.read_slice(&mut bytes)?;
+                this.restore_snapshot(u64::from_ne_bytes(bytes).try_into()?)?;
+                Ok(0)
+            }
+            RUST_DEV_SNAPSHOT_RELEASE => {
+                let mut bytes = [0u8; 8];
+                reader.read_slice(&mut bytes)?;
+                this.release_snapshot(u64::from_ne_bytes(bytes).try_into()?)?;
+                Ok(0)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    fn read_write(
+        this: Self::Target<'_>,
+        _file: &File,
+        cmd: u32,
+        mut data: kernel::user_ptr::UserSlicePtr,
+    ) -> Result<i32> {
+        let want_data = match cmd {
+            RUST_DEV_SEEK_DATA => true,
+            RUST_DEV_SEEK_HOLE => false,
+            _ => return Err(EINVAL),
+        };
+        let (mut reader, mut writer) = data.reader_writer();
+        let mut bytes = [0u8; 8];
+        reader.read_slice(&mut bytes)?;
+        let offset = u64::from_ne_bytes(bytes);
+        let found = this.seek_block(offset, want_data)?;
+        writer.write_slice(&found.to_ne_bytes())?;
+        Ok(0)
+    }
+}
+
 #[vtable]
 impl Operations for Device {
 
@@ -79,63 +486,78 @@ impl Operations for Device {
         this: ArcBorrow<'_, Device>,
         _file: &File,
         user_buff: &mut impl IoBufferWriter,
-        _offset: u64,
+        offset: u64,
     ) -> Result<usize> {
         if user_buff.is_empty() { return Ok(0) }
-        let total_offset;
-        {
-            let curr_pos = this.cursor.lock();
-            let cast : u64 = (*curr_pos).try_into().unwrap();
-            total_offset = _offset.checked_add(cast).unwrap();
-        }
-        let block_index = total_offset.checked_div(BLOCK_SIZE as u64).unwrap();
-        let _rem = total_offset.checked_rem(BLOCK_SIZE as u64).unwrap();
+        let block_index = offset.checked_div(BLOCK_SIZE as u64).unwrap();
+        let _rem = offset.checked_rem(BLOCK_SIZE as u64).unwrap();
         let row : usize = block_index.try_into()?;
         let block_offset : usize = _rem.try_into()?;
-        match this.find_block(row) {
-            Ok(bytes) => {
-                let tot = user_buff.len().checked_add(block_offset).unwrap();
-                let mut end = bytes;
-                if tot < bytes { end = tot; }
-                let dat = this.data.lock();
-                user_buff.write_slice(& dat[row][block_offset..end])?;
-                return Ok(end.saturating_sub(block_offset));
-            },
-            Err(err) => Err(err)
+        // Reads past a fixed capacity report end-of-device.
+        if this.capacity > 0 && row >= this.capacity {
+            return Ok(0);
+        }
+        let (shard_idx, lrow) = this.route(row);
+        let shard = this.shards[shard_idx].lock();
+        let tot = user_buff.len().checked_add(block_offset).unwrap();
+        let mut end = BLOCK_SIZE;
+        if tot < BLOCK_SIZE { end = tot; }
+        let len = end.saturating_sub(block_offset);
+        // An unwritten row is a hole: serve zeros without allocating a block, so
+        // a read keeps the device sparse and leaves SEEK_DATA/SEEK_HOLE and the
+        // size scans honest about what has actually been written.
+        match shard.mapping.get(lrow).copied().flatten() {
+            Some(pid) => user_buff.write_slice(& shard.pool[pid].1[block_offset..end])?,
+            None => user_buff.clear(len)?,
         }
+        Ok(len)
     }
 
     fn write(
         this: ArcBorrow<'_, Device>,
         _file: &File,
         user_buff: &mut impl IoBufferReader,
-        _offset: u64,
+        offset: u64,
     ) -> Result<usize> {
         if user_buff.is_empty() { return Ok(0) }
-        let total_offset;
-        {
-            let curr_pos = this.cursor.lock();
-            let cast : u64 = (*curr_pos).try_into().unwrap();
-            total_offset = _offset.checked_add(cast).unwrap();
-        }
-
-        let block_index = total_offset / BLOCK_SIZE as u64;
-        let _rem = total_offset % BLOCK_SIZE as u64;
+        let block_index = offset / BLOCK_SIZE as u64;
+        let _rem = offset % BLOCK_SIZE as u64;
         let row : usize = block_index.try_into()?;
         let offset : usize = _rem.try_into()?;
-        match this.find_block(row) {
-            Ok(bytes) => {
-                let mut vec = this.data.lock();
-                let tot = user_buff.len().checked_add(offset).unwrap();
-                let mut end = bytes;
-                if tot < bytes { end = tot }
-                user_buff.read_slice(&mut vec[row][offset..end])?;
-                return Ok(end.saturating_sub(offset))
-            },
-            Err(err) => Err(err)
+        let (shard_idx, lrow) = this.route(row);
+        let mut shard = this.shards[shard_idx].lock();
+        // `find_block` enforces the capacity bound and returns `-ENOSPC` for a
+        // row past it, so the mapping never grows beyond the configured size.
+        let pid = shard.find_block(lrow, row, this.capacity)?;
+        // Break sharing before mutating, so snapshots keep their data.
+        let pid = shard.cow_break(lrow, pid)?;
+        let tot = user_buff.len().checked_add(offset).unwrap();
+        let mut end = BLOCK_SIZE;
+        if tot < BLOCK_SIZE { end = tot }
+        user_buff.read_slice(&mut shard.pool[pid].1[offset..end])?;
+        Ok(end.saturating_sub(offset))
+    }
+
+
+    fn seek(this: ArcBorrow<'_, Device>, file: &File, offset: SeekFrom) -> Result<u64> {
+        // The VFS owns the per-`File` position; compute the new absolute offset
+        // and let it store the result. SEEK_END is resolved against the
+        // configured capacity, falling back to the current logical size when the
+        // device grows on demand.
+        let pos = match offset {
+            SeekFrom::Start(off) => off as i64,
+            SeekFrom::Current(delta) => (file.pos() as i64).checked_add(delta).ok_or(EINVAL)?,
+            SeekFrom::End(delta) => (this.capacity_bytes() as i64).checked_add(delta).ok_or(EINVAL)?,
+        };
+        if pos < 0 {
+            return Err(EINVAL);
         }
+        Ok(pos as u64)
     }
 
+    fn ioctl(this: ArcBorrow<'_, Device>, file: &File, cmd: &mut IoctlCommand) -> Result<i32> {
+        cmd.dispatch::<Device>(this, file)
+    }
 
     fn release(_this: Arc<Device>, _: &File) {}
 }
@@ -146,10 +568,13 @@ struct DeviceModule {
 
 impl Module for DeviceModule {
     fn init(name: &'static CStr, _module: &'static ThisModule) -> Result<Self> {
-        let dev = Arc::try_new(Device::try_new()?)?;
+        let cap = *capacity.read();
+        let shard_count = *shards.read();
+        let dev = Arc::try_new(Device::try_new(shard_count, cap)?)?;
         let reg = miscdev::Registration::<Device>::new_pinned(fmt!("{name}"), dev)?;
             pr_debug!("REGISTERING {}\n", fmt!("{name}"));
         Ok(DeviceModule {
             _dev: reg,
         })
-
+    }
+}